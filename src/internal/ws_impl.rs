@@ -1,4 +1,4 @@
-use flate2::read::ZlibDecoder;
+use flate2::{Decompress, FlushDecompress, Status};
 use crate::model::event::WsEvent;
 use crate::gateway::WsClient;
 use crate::internal::prelude::*;
@@ -24,74 +24,224 @@ use std::{
 #[cfg(not(feature = "native_tls_backend"))]
 use url::Url;
 
-pub trait ReceiverExt {
-    fn recv_json(&mut self)     -> Result<Option<(WsEvent, Result<Value>)>>;
-    fn try_recv_json(&mut self) -> Result<Option<(WsEvent, Result<Value>)>>;
-}
+/// The 4-byte suffix that marks the end of a complete zlib-stream message.
+///
+/// Discord's `compress=zlib-stream` transport splits a single deflate stream
+/// across one or more binary frames; this marker is the only signal that a
+/// frame completes a message rather than continuing one.
+const ZLIB_SUFFIX: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
 
-pub trait SenderExt {
-    fn send_json(&mut self, value: &Value) -> Result<()>;
+/// Inflate context shared across every binary frame of a `zlib-stream`
+/// connection, plus a buffer for the fragment currently in flight.
+struct ZlibStreamDecompressor {
+    decompressor: Decompress,
+    input_buffer: Vec<u8>,
+    output_buffer: Vec<u8>,
 }
 
-impl ReceiverExt for WsClient {
-    fn recv_json(&mut self) -> Result<Option<(WsEvent, Result<Value>)>> {
-        Ok(convert_ws_message(Some(self.read_message()?)))
+impl ZlibStreamDecompressor {
+    fn new() -> Self {
+        ZlibStreamDecompressor {
+            decompressor: Decompress::new(true),
+            input_buffer: Vec::new(),
+            output_buffer: Vec::new(),
+        }
     }
 
-    fn try_recv_json(&mut self) -> Result<Option<(WsEvent, Result<Value>)>> {
-        Ok(convert_ws_message(self.read_message().no_block()?))
+    /// Feeds the bytes of a binary frame into the shared inflate context.
+    ///
+    /// Returns the inflated payload once the frame completes a message (i.e.
+    /// the accumulated buffer ends with [`ZLIB_SUFFIX`]), or `None` if the
+    /// message is still being fragmented across further frames.
+    fn decompress(&mut self, bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.input_buffer.extend_from_slice(bytes);
+
+        if self.input_buffer.len() < 4 || self.input_buffer[self.input_buffer.len() - 4..] != ZLIB_SUFFIX {
+            return Ok(None);
+        }
+
+        self.output_buffer.clear();
+
+        // `decompress_vec` only ever writes into the Vec's existing spare
+        // capacity rather than growing it, so we have to reserve a chunk and
+        // loop until a call doesn't fill it (or the stream ends).
+        const CHUNK_SIZE: usize = 8192;
+
+        // `total_in`/`total_out` are cumulative over the decompressor's
+        // entire lifetime, not scoped to this call, so consumption has to be
+        // measured relative to a baseline taken here rather than from zero.
+        let base_in = self.decompressor.total_in();
+
+        loop {
+            let consumed = (self.decompressor.total_in() - base_in) as usize;
+            let before_out = self.decompressor.total_out();
+            self.output_buffer.reserve(CHUNK_SIZE);
+
+            let status = self.decompressor
+                .decompress_vec(
+                    &self.input_buffer[consumed..],
+                    &mut self.output_buffer,
+                    FlushDecompress::Sync,
+                )
+                .map_err(|why| {
+                    warn!("Err inflating zlib-stream message: {:?}", why);
+
+                    why
+                })?;
+
+            let produced = (self.decompressor.total_out() - before_out) as usize;
+
+            if status == Status::StreamEnd || produced < CHUNK_SIZE {
+                break;
+            }
+        }
+
+        // Only the accumulated fragment buffer resets here; the
+        // decompressor's sliding window must survive into the next message.
+        self.input_buffer.clear();
+
+        Ok(Some(std::mem::take(&mut self.output_buffer)))
     }
 }
 
-impl SenderExt for WsClient {
-    fn send_json(&mut self, value: &Value) -> Result<()> {
-        serde_json::to_string(value)
-            .map(Message::Text)
-            .map_err(Error::from)
-            .and_then(|m| self.write_message(m).map_err(Error::from))
-    }
+/// Decoder context shared across every binary frame of a `zstd-stream`
+/// connection. Frame boundaries are zstd's own, not a fixed suffix.
+struct ZstdStreamDecompressor {
+    decoder: zstd::stream::raw::Decoder<'static>,
+    input_buffer: Vec<u8>,
+    output_buffer: Vec<u8>,
 }
 
-#[inline]
-fn convert_ws_message(message: Option<Message>) -> Option<(WsEvent, Result<Value>)>{
-    match message {
-        None => None,
-        Some(msg) => {
-            let raw_event;
-            #[cfg(feature = "raw-ws-event")]
-            {
-                let happened_at_instant = std::time::Instant::now();
-                let happened_at_chrono = ::chrono::Utc::now();
-                raw_event = WsEvent {
-                    happened_at_chrono,
-                    happened_at_instant,
-                    data: msg.clone(),
-                }
-            }
-            #[cfg(not(feature = "raw-ws-event"))]
-            {
-                raw_event = WsEvent;
+impl ZstdStreamDecompressor {
+    fn new() -> Result<Self> {
+        Ok(ZstdStreamDecompressor {
+            decoder: zstd::stream::raw::Decoder::new()?,
+            input_buffer: Vec::new(),
+            output_buffer: Vec::new(),
+        })
+    }
+
+    /// Feeds the bytes of a binary frame into the shared decoder.
+    ///
+    /// Returns the inflated payload once a full zstd frame has been
+    /// decoded, or `None` if the message is still being fragmented across
+    /// further frames.
+    fn decompress(&mut self, bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+        use zstd::stream::raw::{InBuffer, OutBuffer, Operation};
+
+        self.input_buffer.extend_from_slice(bytes);
+
+        let mut input = InBuffer::around(&self.input_buffer);
+        let mut chunk = [0u8; 32 * 1024];
+
+        loop {
+            let mut output = OutBuffer::around(&mut chunk[..]);
+            let remaining_hint = self.decoder.run(&mut input, &mut output)?;
+            self.output_buffer.extend_from_slice(output.as_slice());
+
+            if remaining_hint == 0 {
+                let consumed = input.pos();
+                self.input_buffer.drain(..consumed);
+
+                return Ok(Some(std::mem::take(&mut self.output_buffer)));
             }
 
-            match convert_ws_message_inner(msg).transpose() {
-                None => None,
-                Some(res) => Some((raw_event, res)),
+            if output.as_slice().is_empty() && input.pos() == self.input_buffer.len() {
+                // The frame isn't complete yet; wait for the next fragment.
+                // The decoder has taken in every byte fed so far, so only
+                // the output decoded so far is kept for the next call.
+                self.input_buffer.clear();
+
+                return Ok(None);
             }
         }
     }
 }
-            
+
+/// Negotiated transport-level compression for a gateway connection.
+///
+/// This is decided once, from the connection URL's `compress=` query
+/// parameter, and kept alongside the chosen decoder so the two can never
+/// drift out of sync.
+pub enum TransportCompression {
+    /// No transport compression, or the legacy per-payload `compress=zlib`
+    /// mode, which is handled per-message without any persistent state.
+    None,
+    /// Discord's `compress=zlib-stream` option.
+    ZlibStream,
+    /// Discord's `compress=zstd-stream` option.
+    ZstdStream,
+}
+
+enum Decompressor {
+    None,
+    Zlib(ZlibStreamDecompressor),
+    Zstd(ZstdStreamDecompressor),
+}
+
+#[inline]
+fn convert_ws_message(message: Option<Message>, decompressor: &mut Decompressor) -> Result<Option<(WsEvent, Result<Value>)>> {
+    let msg = match message {
+        None => return Ok(None),
+        Some(msg) => msg,
+    };
+
+    let raw_event;
+    #[cfg(feature = "raw-ws-event")]
+    {
+        let happened_at_instant = std::time::Instant::now();
+        let happened_at_chrono = ::chrono::Utc::now();
+        raw_event = WsEvent {
+            happened_at_chrono,
+            happened_at_instant,
+            data: msg.clone(),
+        }
+    }
+    #[cfg(not(feature = "raw-ws-event"))]
+    {
+        raw_event = WsEvent;
+    }
+
+    match convert_ws_message_inner(msg, decompressor).transpose() {
+        None => Ok(None),
+        Some(res) => Ok(Some((raw_event, res))),
+    }
+}
+
 #[inline]
-fn convert_ws_message_inner(message: Message) -> Result<Option<Value>> {
+fn convert_ws_message_inner(message: Message, decompressor: &mut Decompressor) -> Result<Option<Value>> {
     Ok(match message {
         Message::Binary(bytes) => {
-            serde_json::from_reader(ZlibDecoder::new(&bytes[..]))
-                .map(Some)
-                .map_err(|why| {
-                    warn!("Err deserializing bytes: {:?}; bytes: {:?}", why, bytes);
+            let inflated = match decompressor {
+                Decompressor::Zlib(ref mut zlib) => match zlib.decompress(&bytes)? {
+                    Some(inflated) => inflated,
+                    // The message is still being fragmented across frames.
+                    None => return Ok(None),
+                },
+                Decompressor::Zstd(ref mut zstd) => match zstd.decompress(&bytes)? {
+                    Some(inflated) => inflated,
+                    // The message is still being fragmented across frames.
+                    None => return Ok(None),
+                },
+                // Legacy per-payload compression: a fresh context per message.
+                Decompressor::None => {
+                    let mut decoder = flate2::read::ZlibDecoder::new(&bytes[..]);
+                    let mut inflated = Vec::new();
+                    std::io::Read::read_to_end(&mut decoder, &mut inflated)
+                        .map_err(|why| {
+                            warn!("Err inflating bytes: {:?}; bytes: {:?}", why, bytes);
 
-                    why
-                })?
+                            why
+                        })?;
+                    inflated
+                },
+            };
+
+            serde_json::from_slice(&inflated).map(Some).map_err(|why| {
+                warn!("Err deserializing bytes: {:?}; bytes: {:?}", why, inflated);
+
+                why
+            })?
         },
         Message::Text(payload) => {
             serde_json::from_str(&payload).map(Some).map_err(|why| {
@@ -109,6 +259,76 @@ fn convert_ws_message_inner(message: Message) -> Result<Option<Value>> {
     })
 }
 
+pub trait ReceiverExt {
+    fn recv_json(&mut self) -> Result<Option<(WsEvent, Result<Value>)>>;
+    fn try_recv_json(&mut self) -> Result<Option<(WsEvent, Result<Value>)>>;
+}
+
+impl ReceiverExt for WsClient {
+    fn recv_json(&mut self) -> Result<Option<(WsEvent, Result<Value>)>> {
+        convert_ws_message(Some(self.read_message()?), &mut Decompressor::None)
+    }
+
+    fn try_recv_json(&mut self) -> Result<Option<(WsEvent, Result<Value>)>> {
+        convert_ws_message(self.read_message().no_block()?, &mut Decompressor::None)
+    }
+}
+
+/// Wraps a [`WsClient`] together with the decoder state its `zlib-stream` or
+/// `zstd-stream` transport compression requires.
+///
+/// Those modes' decoder state has to live for the lifetime of the
+/// connection rather than being rebuilt per-message, which a stateless
+/// `ReceiverExt` call can't provide; use `ReceiverExt` directly for
+/// uncompressed or legacy per-payload `compress=zlib` connections instead.
+pub struct Receiver {
+    client: WsClient,
+    decompressor: Decompressor,
+}
+
+impl Receiver {
+    /// Wraps a client, building whatever decoder state `compression` requires.
+    pub fn new(client: WsClient, compression: TransportCompression) -> Result<Self> {
+        let decompressor = match compression {
+            TransportCompression::None => Decompressor::None,
+            TransportCompression::ZlibStream => Decompressor::Zlib(ZlibStreamDecompressor::new()),
+            TransportCompression::ZstdStream => Decompressor::Zstd(ZstdStreamDecompressor::new()?),
+        };
+
+        Ok(Receiver { client, decompressor })
+    }
+
+    /// Returns the underlying client, e.g. to send messages on it.
+    pub fn client_mut(&mut self) -> &mut WsClient {
+        &mut self.client
+    }
+
+    pub fn recv_json(&mut self) -> Result<Option<(WsEvent, Result<Value>)>> {
+        let message = Some(self.client.read_message()?);
+
+        convert_ws_message(message, &mut self.decompressor)
+    }
+
+    pub fn try_recv_json(&mut self) -> Result<Option<(WsEvent, Result<Value>)>> {
+        let message = self.client.read_message().no_block()?;
+
+        convert_ws_message(message, &mut self.decompressor)
+    }
+}
+
+pub trait SenderExt {
+    fn send_json(&mut self, value: &Value) -> Result<()>;
+}
+
+impl SenderExt for WsClient {
+    fn send_json(&mut self, value: &Value) -> Result<()> {
+        serde_json::to_string(value)
+            .map(Message::Text)
+            .map_err(Error::from)
+            .and_then(|m| self.write_message(m).map_err(Error::from))
+    }
+}
+
 /// An error that occured while connecting over rustls
 #[derive(Debug)]
 #[cfg(not(feature = "native_tls_backend"))]
@@ -151,11 +371,137 @@ impl StdError for RustlsError {
     }
 }
 
-// Create a tungstenite client with a rustls stream.
+/// Holds the rustls `ClientConfig` shared by every gateway connection.
 #[cfg(not(feature = "native_tls_backend"))]
-pub(crate) fn create_rustls_client(url: Url) -> Result<WsClient> {
-    let mut config = rustls::ClientConfig::new();
-    config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+struct TlsContainer {
+    config: Arc<rustls::ClientConfig>,
+}
+
+#[cfg(not(feature = "native_tls_backend"))]
+impl TlsContainer {
+    fn new() -> Self {
+        let mut config = rustls::ClientConfig::new();
+        Self::fill_root_store(&mut config.root_store);
+
+        #[cfg(feature = "tls_keylog")]
+        {
+            // Honors the standard `SSLKEYLOGFILE` environment variable so
+            // gateway traffic can be decrypted in Wireshark for debugging.
+            config.key_log = Arc::new(rustls::KeyLogFile::new());
+        }
+
+        TlsContainer { config: Arc::new(config) }
+    }
+
+    /// Populates `root_store` with the operating system's native
+    /// certificate store, so users behind MITM proxies or with internal CAs
+    /// don't have to recompile with their own bundled roots.
+    #[cfg(feature = "rustls_native_roots")]
+    fn fill_root_store(root_store: &mut rustls::RootCertStore) {
+        let loaded = rustls_native_certs::load_native_certs().unwrap_or_else(|(partial, why)| {
+            warn!("Error loading native certs, continuing with partial set: {:?}", why);
+
+            partial.unwrap_or_else(rustls::RootCertStore::empty)
+        });
+
+        *root_store = loaded;
+    }
+
+    #[cfg(not(feature = "rustls_native_roots"))]
+    fn fill_root_store(root_store: &mut rustls::RootCertStore) {
+        root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    }
+
+    fn config(&self) -> Arc<rustls::ClientConfig> {
+        Arc::clone(&self.config)
+    }
+}
+
+#[cfg(not(feature = "native_tls_backend"))]
+lazy_static::lazy_static! {
+    static ref TLS_CONTAINER: TlsContainer = TlsContainer::new();
+}
+
+/// A stream that is either a rustls TLS session or a plain, unencrypted
+/// `TcpStream`, so `wss://` and `ws://` gateway URLs share one `WsClient`.
+#[cfg(not(feature = "native_tls_backend"))]
+pub enum MaybeTlsStream {
+    Tls(rustls::StreamOwned<rustls::ClientSession, TcpStream>),
+    Plain(TcpStream),
+}
+
+#[cfg(not(feature = "native_tls_backend"))]
+impl std::io::Read for MaybeTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        use std::io::Read;
+
+        match self {
+            MaybeTlsStream::Tls(s) => s.read(buf),
+            MaybeTlsStream::Plain(s) => s.read(buf),
+        }
+    }
+}
+
+#[cfg(not(feature = "native_tls_backend"))]
+impl std::io::Write for MaybeTlsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        use std::io::Write;
+
+        match self {
+            MaybeTlsStream::Tls(s) => s.write(buf),
+            MaybeTlsStream::Plain(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        use std::io::Write;
+
+        match self {
+            MaybeTlsStream::Tls(s) => s.flush(),
+            MaybeTlsStream::Plain(s) => s.flush(),
+        }
+    }
+}
+
+/// Gateway-framing knobs passed through to tungstenite's `WebSocketConfig`.
+///
+/// `None` leaves a given knob at tungstenite's own default, so
+/// `WsConfig::default()` keeps every limit at tungstenite's defaults.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WsConfig {
+    pub max_message_size: Option<usize>,
+    pub max_frame_size: Option<usize>,
+    pub accept_unmasked_frames: bool,
+}
+
+impl WsConfig {
+    fn to_tungstenite(self) -> tungstenite::protocol::WebSocketConfig {
+        let default = tungstenite::protocol::WebSocketConfig::default();
+
+        tungstenite::protocol::WebSocketConfig {
+            max_send_queue: None,
+            max_message_size: self.max_message_size.or(default.max_message_size),
+            max_frame_size: self.max_frame_size.or(default.max_frame_size),
+            accept_unmasked_frames: self.accept_unmasked_frames,
+        }
+    }
+}
+
+// Create a tungstenite client, using a rustls stream unless the URL asks
+// for a plain, unencrypted `ws://` connection.
+#[cfg(not(feature = "native_tls_backend"))]
+pub(crate) fn create_rustls_client(url: Url, ws_config: WsConfig) -> Result<WsClient> {
+    let socket = TcpStream::connect(&url)?;
+    let ws_config = ws_config.to_tungstenite();
+
+    if url.scheme() == "ws" {
+        let client = tungstenite::client_with_config(url, MaybeTlsStream::Plain(socket), Some(ws_config))
+            .map_err(|_| RustlsError::HandshakeError)?;
+
+        return Ok(client.0);
+    }
+
+    let config = TLS_CONTAINER.config();
 
     let base_host = if let Some(h) = url.host_str() {
         let (dot, _) = h.rmatch_indices('.').nth(1).unwrap_or((0, ""));
@@ -169,12 +515,80 @@ pub(crate) fn create_rustls_client(url: Url) -> Result<WsClient> {
     let dns_name = webpki::DNSNameRef::try_from_ascii_str(&base_host)
         .map_err(|_| RustlsError::WebPKI)?;
 
-    let session = rustls::ClientSession::new(&Arc::new(config), dns_name);
-    let socket = TcpStream::connect(&url)?;
+    let session = rustls::ClientSession::new(&config, dns_name);
     let tls = rustls::StreamOwned::new(session, socket);
 
-    let client = tungstenite::client(url, tls)
+    let client = tungstenite::client_with_config(url, MaybeTlsStream::Tls(tls), Some(ws_config))
         .map_err(|_| RustlsError::HandshakeError)?;
 
     Ok(client.0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compress, Compression, FlushCompress};
+
+    fn zlib_stream_frame(compressor: &mut Compress, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.reserve(payload.len() + 64);
+        compressor.compress_vec(payload, &mut out, FlushCompress::Sync).unwrap();
+        out
+    }
+
+    #[test]
+    fn zlib_stream_decodes_sequential_messages() {
+        let mut compressor = Compress::new(Compression::default(), true);
+        let mut decompressor = ZlibStreamDecompressor::new();
+
+        let first = zlib_stream_frame(&mut compressor, br#"{"a":1}"#);
+        let decoded = decompressor.decompress(&first).unwrap().unwrap();
+        assert_eq!(decoded, br#"{"a":1}"#);
+
+        let second = zlib_stream_frame(&mut compressor, br#"{"b":2}"#);
+        let decoded = decompressor.decompress(&second).unwrap().unwrap();
+        assert_eq!(decoded, br#"{"b":2}"#);
+    }
+
+    #[test]
+    fn zlib_stream_decodes_fragmented_message() {
+        let mut compressor = Compress::new(Compression::default(), true);
+        let mut decompressor = ZlibStreamDecompressor::new();
+
+        let frame = zlib_stream_frame(&mut compressor, br#"{"a":1}"#);
+        let (first_half, second_half) = frame.split_at(frame.len() / 2);
+
+        assert!(decompressor.decompress(first_half).unwrap().is_none());
+
+        let decoded = decompressor.decompress(second_half).unwrap().unwrap();
+        assert_eq!(decoded, br#"{"a":1}"#);
+    }
+
+    fn zstd_frame(payload: &[u8]) -> Vec<u8> {
+        zstd::encode_all(payload, 0).unwrap()
+    }
+
+    #[test]
+    fn zstd_stream_decodes_sequential_messages() {
+        let mut decompressor = ZstdStreamDecompressor::new().unwrap();
+
+        let decoded = decompressor.decompress(&zstd_frame(br#"{"a":1}"#)).unwrap().unwrap();
+        assert_eq!(decoded, br#"{"a":1}"#);
+
+        let decoded = decompressor.decompress(&zstd_frame(br#"{"b":2}"#)).unwrap().unwrap();
+        assert_eq!(decoded, br#"{"b":2}"#);
+    }
+
+    #[test]
+    fn zstd_stream_decodes_fragmented_message() {
+        let mut decompressor = ZstdStreamDecompressor::new().unwrap();
+
+        let frame = zstd_frame(br#"{"a":1}"#);
+        let (first_half, second_half) = frame.split_at(frame.len() / 2);
+
+        assert!(decompressor.decompress(first_half).unwrap().is_none());
+
+        let decoded = decompressor.decompress(second_half).unwrap().unwrap();
+        assert_eq!(decoded, br#"{"a":1}"#);
+    }
+}